@@ -1,16 +1,56 @@
 use chrono::Utc;
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use serde::Deserialize;
 use serde_json::{json, Value};
+use std::collections::VecDeque;
 use std::fs;
-use std::io::Write;
-use std::path::PathBuf;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Preference between whatever downloads xeno-canto offers for a recording.
+/// The v3 API surfaces at most one `file` URL per recording with no
+/// alternate bitrates/formats to pick between, so today only `mp3-only` has
+/// any real effect (skipping non-mp3 recordings); `best`/`smallest`/`original`
+/// are accepted so the CLI's shape doesn't have to change if xeno-canto ever
+/// starts exposing more than one candidate download, but they currently all
+/// behave the same as `best`. `--format` below can still force the saved
+/// extension regardless of `--quality`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Quality {
+    /// Accept whichever single download xeno-canto returns (default)
+    Best,
+    /// Reserved for multiple candidate downloads; currently identical to `Best`
+    Smallest,
+    /// Reserved for multiple candidate downloads; currently identical to `Best`
+    Original,
+    /// Only accept mp3 downloads; skip the recording otherwise
+    Mp3Only,
+}
 
 #[derive(Parser)]
 #[command(name = "xc-fetch", about = "Fetch recording metadata from xeno-canto API v3")]
-struct Args {
-    /// Xeno-canto catalogue number or recording (e.g. 928094, XC928094, or https://xeno-canto.org/928094)
-    recording: String,
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
 
+#[derive(Subcommand)]
+enum Command {
+    /// Resolve a single catalogue number and fetch it
+    Fetch(FetchArgs),
+    /// Run a xeno-canto query and fetch every match
+    Search(SearchArgs),
+    /// Reconcile index.json against the sidecar files on disk
+    Verify(VerifyArgs),
+}
+
+/// Options shared between `fetch` and `search`.
+#[derive(clap::Args)]
+struct CommonArgs {
     /// Fetch metadata only (skip audio download)
     #[arg(long)]
     metadata_only: bool,
@@ -27,9 +67,118 @@ struct Args {
     #[arg(long)]
     no_index: bool,
 
-    /// API key (overrides XC_API_KEY env var)
+    /// Don't embed xeno-canto attribution/taxonomy tags into the downloaded audio
+    #[arg(long)]
+    no_tag: bool,
+
+    /// Audio quality/format preference (default: `quality` from xc-fetch.toml, or best).
+    /// Only mp3-only has any effect today — xeno-canto's v3 API exposes a single
+    /// download per recording, so best/smallest/original are all equivalent.
+    #[arg(long, value_enum)]
+    quality: Option<Quality>,
+
+    /// Force the audio file extension/format instead of inferring it from xeno-canto's file-name
+    #[arg(long)]
+    format: Option<String>,
+
+    /// Filename template for the base name (before sanitization), with
+    /// {id} {en} {genus} {sp} {date} {cnt} placeholders
+    /// (default: `filename_template` from xc-fetch.toml, or "XC{id} - {en} - {genus} {sp}")
+    #[arg(long)]
+    template: Option<String>,
+
+    /// API key (overrides XC_API_KEY env var; also overrides `api_key` from xc-fetch.toml)
     #[arg(long)]
     key: Option<String>,
+
+    /// Per-request network timeout, in seconds
+    #[arg(long, default_value_t = 30)]
+    timeout: u64,
+
+    /// Max attempts per request before giving up, with exponential backoff between them
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
+}
+
+#[derive(clap::Args)]
+struct FetchArgs {
+    /// Xeno-canto catalogue number or recording (e.g. 928094, XC928094, or https://xeno-canto.org/928094)
+    recording: String,
+
+    #[command(flatten)]
+    common: CommonArgs,
+}
+
+#[derive(clap::Args)]
+struct SearchArgs {
+    /// xeno-canto query (e.g. "gen:Myotis type:echolocation q:A cnt:Spain")
+    query: String,
+
+    /// Cap the number of matched recordings that are processed
+    #[arg(long)]
+    max: Option<usize>,
+
+    /// Print the matched recordings instead of downloading them
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Number of recordings to download concurrently (default: `jobs` from xc-fetch.toml, or 4)
+    #[arg(long)]
+    jobs: Option<usize>,
+
+    #[command(flatten)]
+    common: CommonArgs,
+}
+
+#[derive(clap::Args)]
+struct VerifyArgs {
+    /// Output directory to scan for `*.xc.json` sidecars
+    /// (default: `output_dir` from xc-fetch.toml, or ../../sounds relative to this tool)
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+
+    /// Path to index.json to check/rebuild
+    /// (default: `index` from xc-fetch.toml, or ../../index.json relative to this tool)
+    #[arg(long)]
+    index: Option<PathBuf>,
+
+    /// Rebuild index.json's 'sounds' array from on-disk sidecar metadata instead of just reporting
+    #[arg(long)]
+    fix: bool,
+}
+
+const DEFAULT_FILENAME_TEMPLATE: &str = "XC{id} - {en} - {genus} {sp}";
+
+/// `xc-fetch.toml` discovered the same way `repo_root()` walks upward, supplying
+/// defaults that CLI flags take priority over, and that in turn take priority
+/// over environment variables (currently just `XC_API_KEY`).
+#[derive(Deserialize, Default)]
+struct FileConfig {
+    api_key: Option<String>,
+    output_dir: Option<PathBuf>,
+    index: Option<PathBuf>,
+    quality: Option<Quality>,
+    jobs: Option<usize>,
+    filename_template: Option<String>,
+}
+
+fn load_config() -> FileConfig {
+    let Some(dir) = find_upward("xc-fetch.toml") else {
+        return FileConfig::default();
+    };
+    let path = dir.join("xc-fetch.toml");
+
+    let content = fs::read_to_string(&path).expect("Failed to read xc-fetch.toml");
+    match toml::from_str(&content) {
+        Ok(config) => {
+            eprintln!("Loaded config from {}", path.display());
+            config
+        }
+        Err(e) => {
+            eprintln!("Warning: couldn't parse {}: {}", path.display(), e);
+            FileConfig::default()
+        }
+    }
 }
 
 fn sanitize_filename(name: &str) -> String {
@@ -68,14 +217,13 @@ fn parse_xc_number(input: &str) -> Result<u64, String> {
     Err(format!("Can't parse XC number from: {s}"))
 }
 
-/// Resolve default paths relative to the tool's own location (tools/xc-fetch/ -> repo root)
-fn repo_root() -> Option<PathBuf> {
+/// Walk upward from the tool's own location (tools/xc-fetch/target/{debug,release}/
+/// -> repo root) looking for `filename`, returning the directory it was found in.
+fn find_upward(filename: &str) -> Option<PathBuf> {
     let exe = std::env::current_exe().ok()?;
-    // exe is in tools/xc-fetch/target/debug/ or tools/xc-fetch/target/release/
-    // Walk up to find index.json
     let mut dir = exe.parent()?;
     for _ in 0..6 {
-        if dir.join("index.json").exists() {
+        if dir.join(filename).exists() {
             return Some(dir.to_path_buf());
         }
         dir = dir.parent()?;
@@ -83,6 +231,11 @@ fn repo_root() -> Option<PathBuf> {
     None
 }
 
+/// Resolve default paths relative to the tool's own location (tools/xc-fetch/ -> repo root)
+fn repo_root() -> Option<PathBuf> {
+    find_upward("index.json")
+}
+
 fn update_index(index_path: &PathBuf, xc_id: u64, en: &str, genus: &str, sp: &str, audio_filename: &str, meta_filename: &str) {
     let mut index: Value = if index_path.exists() {
         let content = fs::read_to_string(index_path).expect("Failed to read index.json");
@@ -113,74 +266,253 @@ fn update_index(index_path: &PathBuf, xc_id: u64, en: &str, genus: &str, sp: &st
     eprintln!("Updated {}", index_path.display());
 }
 
-fn main() {
-    // Load .env from current dir or any parent (walks up to repo root)
-    let _ = dotenvy::dotenv();
+/// Apply a whole batch of new entries to index.json under a single
+/// read-modify-write, so concurrent downloads don't race each other (or a
+/// sequential loop of `update_index` calls) into clobbering the file.
+fn update_index_batch(index_path: &PathBuf, entries: &[RecordingFiles]) {
+    if entries.is_empty() {
+        return;
+    }
 
-    let args = Args::parse();
+    let mut index: Value = if index_path.exists() {
+        let content = fs::read_to_string(index_path).expect("Failed to read index.json");
+        serde_json::from_str(&content).expect("Failed to parse index.json")
+    } else {
+        json!({ "version": 1, "sounds": [] })
+    };
 
-    let xc_number = parse_xc_number(&args.recording)
-        .unwrap_or_else(|e| { eprintln!("{e}"); std::process::exit(1); });
+    let sounds = index["sounds"].as_array_mut().expect("index.json 'sounds' is not an array");
+    let mut added = 0;
 
-    let api_key = args
-        .key
-        .or_else(|| std::env::var("XC_API_KEY").ok())
-        .expect("API key required: pass --key, set XC_API_KEY env var, or add it to .env");
+    for files in entries {
+        if sounds.iter().any(|s| s["xc_id"].as_u64() == Some(files.xc_id)) {
+            eprintln!("XC{} already in index.json, skipping", files.xc_id);
+            continue;
+        }
 
-    let url = format!(
-        "https://xeno-canto.org/api/3/recordings?query=nr:{}&key={}",
-        xc_number, api_key
-    );
+        sounds.push(json!({
+            "filename": files.audio_filename,
+            "metadata": files.meta_filename,
+            "xc_id": files.xc_id,
+            "en": files.en,
+            "species": format!("{} {}", files.genus, files.sp),
+            "source": "xeno-canto"
+        }));
+        added += 1;
+    }
 
-    eprintln!("Fetching XC{}...", xc_number);
+    if added == 0 {
+        return;
+    }
 
-    let client = reqwest::blocking::Client::new();
-    let resp = client
-        .get(&url)
-        .send()
-        .expect("Failed to send request");
+    let json_str = serde_json::to_string_pretty(&index).expect("Failed to serialize index.json");
+    fs::write(index_path, format!("{}\n", json_str)).expect("Failed to write index.json");
+    eprintln!("Updated {} ({} new entries)", index_path.display(), added);
+}
 
-    if !resp.status().is_success() {
-        eprintln!("HTTP error: {}", resp.status());
-        let body = resp.text().unwrap_or_default();
-        eprintln!("{body}");
-        std::process::exit(1);
+/// Send a request built fresh by `build` on every attempt, retrying up to
+/// `retries` attempts total with exponential backoff (1s, 2s, 4s, ..., capped
+/// at 64s so a large `--retries` degrades gracefully instead of sleeping for
+/// hours or overflowing the shift once `attempt` gets large).
+/// Only timeouts, connection errors, and 5xx/429 responses are retried;
+/// successes and other 4xx responses are returned immediately.
+fn send_with_retry<F>(build: F, retries: u32) -> Result<reqwest::blocking::Response, reqwest::Error>
+where
+    F: Fn() -> reqwest::blocking::RequestBuilder,
+{
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        match build().send() {
+            Ok(resp) => {
+                let status = resp.status();
+                let retryable = status.is_server_error() || status.as_u16() == 429;
+                if !retryable || attempt >= retries {
+                    return Ok(resp);
+                }
+                eprintln!("HTTP {status}, retrying ({attempt}/{retries})...");
+            }
+            Err(e) => {
+                if attempt >= retries || !(e.is_timeout() || e.is_connect()) {
+                    return Err(e);
+                }
+                eprintln!("Request error: {e}, retrying ({attempt}/{retries})...");
+            }
+        }
+        std::thread::sleep(Duration::from_secs(1 << (attempt - 1).min(6)));
     }
+}
 
-    let body: Value = resp.json().expect("Failed to parse JSON response");
+/// Run a xeno-canto v3 query, paging through `numPages` and accumulating every
+/// matching recording into one Vec. `max`, if given, stops once that many
+/// recordings have been collected (the in-flight page is still fetched in
+/// full, then truncated).
+fn run_search_query(client: &reqwest::blocking::Client, query: &str, api_key: &str, max: Option<usize>, retries: u32) -> Vec<Value> {
+    let mut recordings = Vec::new();
+    let mut page = 1u64;
 
-    if let Some(err) = body.get("error") {
-        eprintln!("API error: {}", err);
-        std::process::exit(1);
-    }
+    loop {
+        eprintln!("Fetching page {}...", page);
 
-    let recordings = body["recordings"]
-        .as_array()
-        .expect("Expected 'recordings' array in response");
+        let resp = send_with_retry(
+            || {
+                client.get("https://xeno-canto.org/api/3/recordings").query(&[
+                    ("query", query.to_string()),
+                    ("page", page.to_string()),
+                    ("key", api_key.to_string()),
+                ])
+            },
+            retries,
+        )
+        .expect("Failed to send request");
 
-    if recordings.is_empty() {
-        eprintln!("No recordings found for XC{}", xc_number);
-        std::process::exit(1);
+        if !resp.status().is_success() {
+            eprintln!("HTTP error: {}", resp.status());
+            let body = resp.text().unwrap_or_default();
+            eprintln!("{body}");
+            std::process::exit(1);
+        }
+
+        let body: Value = resp.json().expect("Failed to parse JSON response");
+
+        if let Some(err) = body.get("error") {
+            eprintln!("API error: {}", err);
+            std::process::exit(1);
+        }
+
+        let num_pages = body["numPages"].as_u64().unwrap_or(1);
+        let page_recordings = body["recordings"]
+            .as_array()
+            .expect("Expected 'recordings' array in response");
+
+        recordings.extend(page_recordings.iter().cloned());
+
+        if let Some(max) = max {
+            if recordings.len() >= max {
+                recordings.truncate(max);
+                break;
+            }
+        }
+
+        if page >= num_pages {
+            break;
+        }
+        page += 1;
     }
 
-    let rec = &recordings[0];
+    eprintln!(
+        "Matched {} recording(s) across {} page(s)",
+        recordings.len(),
+        page
+    );
+
+    recordings
+}
+
+/// Embed xeno-canto attribution and taxonomy into the downloaded audio file
+/// using the container's primary tag type (ID3v2 for mp3/wav, Vorbis comments
+/// for ogg/flac), in addition to the
+/// sidecar `.xc.json`. Unsupported/unparsable containers are skipped with a
+/// warning rather than aborting the fetch.
+fn write_audio_tags(path: &Path, rec: &Value, attribution: &str) {
+    use lofty::file::TaggedFileExt;
+    use lofty::probe::Probe;
+    use lofty::tag::{Accessor, ItemKey};
+
+    let mut tagged_file = match Probe::open(path).and_then(|p| p.read()) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("Warning: couldn't tag {} ({}), leaving untagged", path.display(), e);
+            return;
+        }
+    };
+
+    let tag_type = tagged_file.primary_tag_type();
+    if tagged_file.tag(tag_type).is_none() {
+        tagged_file.insert_tag(lofty::tag::Tag::new(tag_type));
+    }
+    let tag = tagged_file.tag_mut(tag_type).expect("tag was just inserted");
 
-    let id = rec["id"].as_str().unwrap_or("");
-    let genus = rec["gen"].as_str().unwrap_or("");
-    let sp = rec["sp"].as_str().unwrap_or("");
     let en = rec["en"].as_str().unwrap_or("");
     let recordist = rec["rec"].as_str().unwrap_or("");
+    let genus = rec["gen"].as_str().unwrap_or("");
+    let sp = rec["sp"].as_str().unwrap_or("");
+    let xc_id = rec["id"].as_str().unwrap_or("");
     let lic = rec["lic"].as_str().unwrap_or("");
 
-    let base_name = sanitize_filename(&format!("XC{} - {} - {} {}", id, en, genus, sp));
+    tag.set_title(en.to_string());
+    tag.set_artist(recordist.to_string());
+    tag.set_album("xeno-canto".to_string());
+    tag.set_comment(attribution.to_string());
+    tag.set_genre(format!("{genus} {sp}"));
+    tag.insert_text(ItemKey::Unknown("XC_ID".to_string()), xc_id.to_string());
+    tag.insert_text(ItemKey::Unknown("LICENSE".to_string()), lic.to_string());
+
+    if let Err(e) = tagged_file.save_to_path(path, lofty::config::WriteOptions::default()) {
+        eprintln!("Warning: couldn't save tags to {}: {}", path.display(), e);
+    }
+}
+
+/// Filenames and index fields derived from a single `recordings[]` entry,
+/// shared between the metadata-writing step, the audio download, and the
+/// eventual `update_index` call.
+struct RecordingFiles {
+    id: String,
+    genus: String,
+    sp: String,
+    en: String,
+    recordist: String,
+    lic: String,
+    audio_filename: String,
+    meta_filename: String,
+    attribution: String,
+    xc_id: u64,
+    format: String,
+    sample_rate: Option<u64>,
+}
 
-    // Determine extension from file-name field or default to .wav
-    let ext = rec["file-name"]
+/// Pick the extension/format to store a recording under, honoring
+/// `--format` when given and otherwise inferring it from xeno-canto's
+/// `file-name` field. Returns an error when `quality` can't be satisfied
+/// (currently only `Quality::Mp3Only` against a non-mp3 recording), since
+/// the v3 API exposes at most one download per recording to choose from.
+fn resolve_format(rec: &Value, quality: Quality, format_override: Option<&str>) -> Result<String, String> {
+    let inferred = rec["file-name"]
         .as_str()
         .and_then(|name| name.rsplit('.').next())
         .unwrap_or("wav");
 
-    let audio_filename = format!("{}.{}", base_name, ext);
+    if quality == Quality::Mp3Only && !inferred.eq_ignore_ascii_case("mp3") {
+        return Err(format!("only {inferred} is available, but --quality mp3-only was requested"));
+    }
+
+    Ok(format_override.unwrap_or(inferred).to_lowercase())
+}
+
+/// Expand a filename template's `{id} {en} {genus} {sp} {date} {cnt}`
+/// placeholders against a recording. The result still passes through
+/// `sanitize_filename` before being used as a base name.
+fn render_filename_template(template: &str, rec: &Value) -> String {
+    template
+        .replace("{id}", rec["id"].as_str().unwrap_or(""))
+        .replace("{en}", rec["en"].as_str().unwrap_or(""))
+        .replace("{genus}", rec["gen"].as_str().unwrap_or(""))
+        .replace("{sp}", rec["sp"].as_str().unwrap_or(""))
+        .replace("{date}", rec["date"].as_str().unwrap_or(""))
+        .replace("{cnt}", rec["cnt"].as_str().unwrap_or(""))
+}
+
+fn recording_files(rec: &Value, format: String, filename_template: &str) -> RecordingFiles {
+    let id = rec["id"].as_str().unwrap_or("").to_string();
+    let genus = rec["gen"].as_str().unwrap_or("").to_string();
+    let sp = rec["sp"].as_str().unwrap_or("").to_string();
+    let en = rec["en"].as_str().unwrap_or("").to_string();
+    let recordist = rec["rec"].as_str().unwrap_or("").to_string();
+    let lic = rec["lic"].as_str().unwrap_or("").to_string();
+
+    let base_name = sanitize_filename(&render_filename_template(filename_template, rec));
+
+    let audio_filename = format!("{}.{}", base_name, format);
     let meta_filename = format!("{}.xc.json", base_name);
 
     let attribution = format!(
@@ -188,15 +520,38 @@ fn main() {
         recordist, id, id
     );
 
+    let xc_id = rec["id"].as_str().and_then(|s| s.parse::<u64>().ok()).unwrap_or(0);
+    let sample_rate = rec["smp"].as_str().and_then(|s| s.parse::<u64>().ok());
+
+    RecordingFiles {
+        id,
+        genus,
+        sp,
+        en,
+        recordist,
+        lic,
+        audio_filename,
+        meta_filename,
+        attribution,
+        xc_id,
+        format,
+        sample_rate,
+    }
+}
+
+/// Write the sidecar `.xc.json` for a recording, returning the path written.
+/// Records `format` and `sample_rate` (from xeno-canto's `smp`); there's no
+/// `bitrate` field because the v3 API doesn't report one per recording.
+fn write_metadata_json(output_dir: &Path, files: &RecordingFiles, rec: &Value) -> PathBuf {
     let metadata = json!({
         "source": "xeno-canto",
-        "xc_id": rec["id"].as_str().and_then(|s| s.parse::<u64>().ok()).unwrap_or(xc_number),
-        "url": format!("https://www.xeno-canto.org/{}", id),
+        "xc_id": files.xc_id,
+        "url": format!("https://www.xeno-canto.org/{}", files.id),
         "file_url": rec["file"],
-        "gen": genus,
-        "sp": sp,
-        "en": en,
-        "rec": recordist,
+        "gen": files.genus,
+        "sp": files.sp,
+        "en": files.en,
+        "rec": files.recordist,
         "cnt": rec["cnt"],
         "loc": rec["loc"],
         "lat": rec["lat"],
@@ -207,67 +562,602 @@ fn main() {
         "q": rec["q"],
         "length": rec["length"],
         "smp": rec["smp"].as_str().and_then(|s| s.parse::<u64>().ok()),
-        "lic": lic,
-        "attribution": attribution,
+        "format": files.format,
+        "sample_rate": files.sample_rate,
+        "lic": files.lic,
+        "attribution": files.attribution,
         "retrieved": Utc::now().format("%Y-%m-%d").to_string(),
         "raw_response": rec,
     });
 
-    // Resolve output directory
-    let output_dir = args.output_dir.unwrap_or_else(|| {
-        repo_root()
-            .map(|r| r.join("sounds"))
-            .unwrap_or_else(|| PathBuf::from("."))
-    });
-    fs::create_dir_all(&output_dir).expect("Failed to create output directory");
+    fs::create_dir_all(output_dir).expect("Failed to create output directory");
 
-    // Write metadata JSON
-    let json_path = output_dir.join(&meta_filename);
+    let json_path = output_dir.join(&files.meta_filename);
     let json_bytes = serde_json::to_string_pretty(&metadata).expect("Failed to serialize JSON");
     fs::write(&json_path, format!("{}\n", json_bytes)).expect("Failed to write metadata JSON");
+    json_path
+}
+
+/// Download `url` into `dest`, resuming a previous attempt if a `{dest}.part`
+/// file is already present. Resumption sends `Range: bytes={len}-` and
+/// requires the server to answer `206 Partial Content`; if it answers
+/// `416 Range Not Satisfiable`, the `.part` file already holds every byte
+/// (the process died after the last write but before the rename), so it's
+/// renamed into place directly. Any other successful response to a resume
+/// attempt (e.g. the server ignores Range and answers 200) overwrites the
+/// partial file from scratch instead; a non-success status is returned as an
+/// error. On success the `.part` file is atomically renamed into place.
+/// Requests are retried per `send_with_retry`; when `pb` is given, bytes
+/// received are reported on it.
+fn download_audio_resumable(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    dest: &Path,
+    retries: u32,
+    pb: Option<&ProgressBar>,
+) -> Result<(), String> {
+    let part_filename = format!("{}.part", dest.file_name().unwrap().to_string_lossy());
+    let part_path = dest.with_file_name(part_filename);
+
+    let resume_from = fs::metadata(&part_path).map(|m| m.len()).unwrap_or(0);
+
+    let mut resp = send_with_retry(
+        || {
+            let req = client.get(url);
+            if resume_from > 0 {
+                req.header(reqwest::header::RANGE, format!("bytes={}-", resume_from))
+            } else {
+                req
+            }
+        },
+        retries,
+    )
+    .map_err(|e| e.to_string())?;
+
+    // A resume whose `.part` file already holds every byte (e.g. the process
+    // was killed after the last write but before the final rename) asks for
+    // a byte range past the end of the file, which the server answers with
+    // 416 rather than 206/200. Treat that as "already downloaded" instead of
+    // a failure.
+    if resume_from > 0 && resp.status().as_u16() == 416 {
+        return fs::rename(&part_path, dest).map_err(|e| e.to_string());
+    }
+
+    if !resp.status().is_success() {
+        return Err(format!("HTTP {}", resp.status()));
+    }
+
+    let resuming = resume_from > 0 && resp.status().as_u16() == 206;
+
+    let mut file = if resuming {
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&part_path)
+            .map_err(|e| e.to_string())?
+    } else {
+        fs::File::create(&part_path).map_err(|e| e.to_string())?
+    };
+
+    if let Some(pb) = pb {
+        let total = resp.content_length().unwrap_or(0) + if resuming { resume_from } else { 0 };
+        pb.set_length(total);
+        pb.set_position(if resuming { resume_from } else { 0 });
+    }
+
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = resp.read(&mut buf).map_err(|e| e.to_string())?;
+        if n == 0 {
+            break;
+        }
+        file.write_all(&buf[..n]).map_err(|e| e.to_string())?;
+        if let Some(pb) = pb {
+            pb.inc(n as u64);
+        }
+    }
+
+    fs::rename(&part_path, dest).map_err(|e| e.to_string())
+}
+
+/// Write metadata JSON, optionally download the audio, and update index.json
+/// for a single recording resolved by the `fetch` subcommand's `nr:` lookup.
+fn process_recording(
+    client: &reqwest::blocking::Client,
+    rec: &Value,
+    metadata_only: bool,
+    no_tag: bool,
+    quality: Quality,
+    format_override: Option<&str>,
+    filename_template: &str,
+    retries: u32,
+    output_dir: &PathBuf,
+    index_path: Option<&PathBuf>,
+) {
+    let format = match resolve_format(rec, quality, format_override) {
+        Ok(format) => format,
+        Err(e) => {
+            eprintln!("Skipping XC{}: {}", rec["id"].as_str().unwrap_or("?"), e);
+            return;
+        }
+    };
+    let files = recording_files(rec, format, filename_template);
+
+    let json_path = write_metadata_json(output_dir, &files, rec);
     eprintln!("Wrote {}", json_path.display());
 
     // Download audio
-    if !args.metadata_only {
+    if !metadata_only {
         let file_url = rec["file"]
             .as_str()
             .expect("No 'file' URL in recording data");
 
-        let audio_path = output_dir.join(&audio_filename);
+        let audio_path = output_dir.join(&files.audio_filename);
 
         eprintln!("Downloading audio...");
-        let audio_resp = client
-            .get(file_url)
-            .send()
-            .expect("Failed to download audio");
-
-        if !audio_resp.status().is_success() {
-            eprintln!("Failed to download audio: HTTP {}", audio_resp.status());
+        if let Err(e) = download_audio_resumable(client, file_url, &audio_path, retries, None) {
+            eprintln!("Failed to download audio: {e}");
             std::process::exit(1);
         }
+        let size = fs::metadata(&audio_path).map(|m| m.len()).unwrap_or(0);
+        eprintln!("Wrote {} ({:.1} MB)", audio_path.display(), size as f64 / 1_048_576.0);
 
-        let audio_bytes = audio_resp.bytes().expect("Failed to read audio bytes");
-        let mut file = fs::File::create(&audio_path).expect("Failed to create audio file");
-        file.write_all(&audio_bytes)
-            .expect("Failed to write audio file");
-        eprintln!("Wrote {} ({:.1} MB)", audio_path.display(), audio_bytes.len() as f64 / 1_048_576.0);
+        if !no_tag {
+            write_audio_tags(&audio_path, rec, &files.attribution);
+        }
     }
 
     // Update index.json
-    if !args.no_index {
-        let index_path = args.index.unwrap_or_else(|| {
+    if let Some(index_path) = index_path {
+        update_index(index_path, files.xc_id, &files.en, &files.genus, &files.sp, &files.audio_filename, &files.meta_filename);
+    }
+
+    // Print summary
+    println!("XC{}: {} ({} {})", files.id, files.en, files.genus, files.sp);
+    println!("Recordist: {}", files.recordist);
+    println!("License: {}", files.lic);
+    println!("Attribution: {}", files.attribution);
+}
+
+/// Download a single recording's audio with a per-file progress bar tracked
+/// against its `Content-Length`, writing metadata first as usual.
+/// Used by `run_batch_download`'s worker pool; `process_recording` keeps its
+/// own simpler sequential path for the single-recording case.
+///
+/// Returns `None` (and leaves no entry for callers to index) when the
+/// recording is skipped by `resolve_format` or its audio download fails;
+/// with `metadata_only` the entry is returned without any audio. Mirrors `process_recording`'s single-fetch
+/// path aborting before `update_index` on failure.
+fn download_one(
+    client: &reqwest::blocking::Client,
+    rec: &Value,
+    metadata_only: bool,
+    no_tag: bool,
+    quality: Quality,
+    format_override: Option<&str>,
+    filename_template: &str,
+    retries: u32,
+    output_dir: &Path,
+    multi: &MultiProgress,
+) -> Option<RecordingFiles> {
+    let format = match resolve_format(rec, quality, format_override) {
+        Ok(format) => format,
+        Err(e) => {
+            let _ = multi.println(format!("Skipping XC{}: {}", rec["id"].as_str().unwrap_or("?"), e));
+            return None;
+        }
+    };
+    let files = recording_files(rec, format, filename_template);
+    let json_path = write_metadata_json(output_dir, &files, rec);
+    let _ = multi.println(format!("Wrote {}", json_path.display()));
+
+    if metadata_only {
+        return Some(files);
+    }
+
+    let file_url = rec["file"].as_str().expect("No 'file' URL in recording data");
+    let audio_path = output_dir.join(&files.audio_filename);
+
+    let pb = multi.add(ProgressBar::new(0));
+    pb.set_style(
+        ProgressStyle::with_template("{msg:.dim} [{bar:30}] {bytes}/{total_bytes}")
+            .unwrap()
+            .progress_chars("=> "),
+    );
+    pb.set_message(files.audio_filename.clone());
+
+    if let Err(e) = download_audio_resumable(client, file_url, &audio_path, retries, Some(&pb)) {
+        pb.finish_with_message(format!("{} failed: {e}", files.audio_filename));
+        let _ = multi.println(format!("Failed to download XC{}: {e}", files.xc_id));
+        return None;
+    }
+    pb.finish_with_message(format!("{} done", files.audio_filename));
+
+    if !no_tag {
+        write_audio_tags(&audio_path, rec, &files.attribution);
+    }
+
+    Some(files)
+}
+
+/// Download a batch of recordings concurrently: a bounded pool of `jobs`
+/// worker threads pulls from a shared queue, each rendering its own progress
+/// bar under one `MultiProgress`, plus an overall completed/total counter.
+/// Returns the saved entries without touching `index.json`; the caller applies
+/// them once at the end via `update_index_batch`.
+fn run_batch_download(
+    client: &reqwest::blocking::Client,
+    recordings: &[Value],
+    jobs: usize,
+    metadata_only: bool,
+    no_tag: bool,
+    quality: Quality,
+    format_override: Option<&str>,
+    filename_template: &str,
+    retries: u32,
+    output_dir: &Path,
+) -> Vec<RecordingFiles> {
+    let multi = MultiProgress::new();
+    let overall = multi.add(ProgressBar::new(recordings.len() as u64));
+    overall.set_style(
+        ProgressStyle::with_template("{msg} [{bar:30.cyan/blue}] {pos}/{len}").unwrap(),
+    );
+    overall.set_message("overall");
+
+    let queue: Arc<Mutex<VecDeque<&Value>>> = Arc::new(Mutex::new(recordings.iter().collect()));
+    let results: Arc<Mutex<Vec<RecordingFiles>>> = Arc::new(Mutex::new(Vec::new()));
+
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            let queue = Arc::clone(&queue);
+            let results = Arc::clone(&results);
+            let multi = &multi;
+            let overall = &overall;
+            scope.spawn(move || loop {
+                let rec = match queue.lock().unwrap().pop_front() {
+                    Some(rec) => rec,
+                    None => break,
+                };
+                let files = download_one(client, rec, metadata_only, no_tag, quality, format_override, filename_template, retries, output_dir, multi);
+                if let Some(files) = files {
+                    results.lock().unwrap().push(files);
+                }
+                overall.inc(1);
+            });
+        }
+    });
+
+    overall.finish_with_message("all downloads complete");
+    Arc::try_unwrap(results).unwrap().into_inner().unwrap()
+}
+
+/// Everything derived from `CommonArgs` plus `xc-fetch.toml`/the environment
+/// that `fetch` and `search` both need before they can talk to the API.
+struct Resolved {
+    api_key: String,
+    quality: Quality,
+    filename_template: String,
+    output_dir: PathBuf,
+    index_path: Option<PathBuf>,
+    /// For metadata requests: `--timeout` bounds the whole request, since
+    /// responses are small and a stalled one genuinely indicates trouble.
+    client: reqwest::blocking::Client,
+    /// For audio downloads: only the TCP connect is bounded by `--timeout`.
+    /// Applying it as a whole-request timeout would abort any file whose
+    /// transfer legitimately takes longer than that many seconds, turning
+    /// the resumable-download path into the common case instead of the
+    /// fallback it's meant to be.
+    download_client: reqwest::blocking::Client,
+}
+
+fn resolve_common(common: &CommonArgs, config: &FileConfig) -> Resolved {
+    // CLI flags override the config file, which overrides the environment.
+    let api_key = common
+        .key
+        .clone()
+        .or_else(|| config.api_key.clone())
+        .or_else(|| std::env::var("XC_API_KEY").ok())
+        .expect("API key required: pass --key, set XC_API_KEY env var, add it to .env, or set api_key in xc-fetch.toml");
+
+    let quality = common.quality.or(config.quality).unwrap_or(Quality::Best);
+    let filename_template = common
+        .template
+        .clone()
+        .or_else(|| config.filename_template.clone())
+        .unwrap_or_else(|| DEFAULT_FILENAME_TEMPLATE.to_string());
+
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(common.timeout))
+        .build()
+        .expect("Failed to build HTTP client");
+
+    let download_client = reqwest::blocking::Client::builder()
+        .connect_timeout(Duration::from_secs(common.timeout))
+        .build()
+        .expect("Failed to build HTTP client");
+
+    let output_dir = common
+        .output_dir
+        .clone()
+        .or_else(|| config.output_dir.clone())
+        .unwrap_or_else(|| {
+            repo_root()
+                .map(|r| r.join("sounds"))
+                .unwrap_or_else(|| PathBuf::from("."))
+        });
+
+    let index_path = if common.no_index {
+        None
+    } else {
+        Some(
+            common
+                .index
+                .clone()
+                .or_else(|| config.index.clone())
+                .unwrap_or_else(|| {
+                    repo_root()
+                        .map(|r| r.join("index.json"))
+                        .unwrap_or_else(|| output_dir.join("../index.json"))
+                }),
+        )
+    };
+
+    Resolved {
+        api_key,
+        quality,
+        filename_template,
+        output_dir,
+        index_path,
+        client,
+        download_client,
+    }
+}
+
+fn run_fetch(args: FetchArgs, config: &FileConfig) {
+    let resolved = resolve_common(&args.common, config);
+
+    let xc_number = parse_xc_number(&args.recording)
+        .unwrap_or_else(|e| { eprintln!("{e}"); std::process::exit(1); });
+
+    eprintln!("Fetching XC{}...", xc_number);
+
+    let resp = send_with_retry(
+        || {
+            resolved.client.get("https://xeno-canto.org/api/3/recordings").query(&[
+                ("query", format!("nr:{}", xc_number)),
+                ("key", resolved.api_key.clone()),
+            ])
+        },
+        args.common.retries,
+    )
+    .expect("Failed to send request");
+
+    if !resp.status().is_success() {
+        eprintln!("HTTP error: {}", resp.status());
+        let body = resp.text().unwrap_or_default();
+        eprintln!("{body}");
+        std::process::exit(1);
+    }
+
+    let body: Value = resp.json().expect("Failed to parse JSON response");
+
+    if let Some(err) = body.get("error") {
+        eprintln!("API error: {}", err);
+        std::process::exit(1);
+    }
+
+    let recordings = body["recordings"]
+        .as_array()
+        .expect("Expected 'recordings' array in response");
+
+    if recordings.is_empty() {
+        eprintln!("No recordings found for XC{}", xc_number);
+        std::process::exit(1);
+    }
+
+    process_recording(
+        &resolved.download_client,
+        &recordings[0],
+        args.common.metadata_only,
+        args.common.no_tag,
+        resolved.quality,
+        args.common.format.as_deref(),
+        &resolved.filename_template,
+        args.common.retries,
+        &resolved.output_dir,
+        resolved.index_path.as_ref(),
+    );
+}
+
+fn run_search(args: SearchArgs, config: &FileConfig) {
+    let resolved = resolve_common(&args.common, config);
+    let jobs = args.jobs.or(config.jobs).unwrap_or(4);
+
+    let recordings = run_search_query(&resolved.client, &args.query, &resolved.api_key, args.max, args.common.retries);
+
+    if recordings.is_empty() {
+        eprintln!("No recordings matched query: {}", args.query);
+        std::process::exit(1);
+    }
+
+    if args.dry_run {
+        for rec in &recordings {
+            println!(
+                "XC{}: {} ({} {}) [{}] q={}",
+                rec["id"].as_str().unwrap_or("?"),
+                rec["en"].as_str().unwrap_or(""),
+                rec["gen"].as_str().unwrap_or(""),
+                rec["sp"].as_str().unwrap_or(""),
+                rec["length"].as_str().unwrap_or("?"),
+                rec["q"].as_str().unwrap_or("?"),
+            );
+        }
+        return;
+    }
+
+    let entries = run_batch_download(
+        &resolved.download_client,
+        &recordings,
+        jobs,
+        args.common.metadata_only,
+        args.common.no_tag,
+        resolved.quality,
+        args.common.format.as_deref(),
+        &resolved.filename_template,
+        args.common.retries,
+        &resolved.output_dir,
+    );
+    if let Some(index_path) = &resolved.index_path {
+        update_index_batch(index_path, &entries);
+    }
+}
+
+/// Read every `*.xc.json` sidecar in `output_dir`, pairing each with the
+/// `Value` parsed from it. Files that fail to parse are skipped with a
+/// warning rather than aborting the whole scan.
+fn scan_sidecars(output_dir: &Path) -> Vec<(PathBuf, Value)> {
+    let mut sidecars = Vec::new();
+    let Ok(entries) = fs::read_dir(output_dir) else {
+        return sidecars;
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.ends_with(".xc.json")) {
+            match fs::read_to_string(&path).ok().and_then(|s| serde_json::from_str::<Value>(&s).ok()) {
+                Some(meta) => sidecars.push((path, meta)),
+                None => eprintln!("Warning: couldn't parse {}, skipping", path.display()),
+            }
+        }
+    }
+
+    sidecars
+}
+
+/// Find the audio file paired with a `{base}.xc.json` sidecar by looking for
+/// any `{base}.*` entry in `output_dir`, rather than guessing the extension
+/// from the sidecar's `format` field (which is absent from sidecars written
+/// before `--quality`/`--format` started recording it). Skips the sidecar
+/// itself and any in-progress `.part` download.
+fn find_audio_file(output_dir: &Path, base: &str) -> Option<PathBuf> {
+    let prefix = format!("{base}.");
+    fs::read_dir(output_dir).ok()?.flatten().find_map(|entry| {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with(&prefix) && !name.ends_with(".xc.json") && !name.ends_with(".part") {
+            Some(entry.path())
+        } else {
+            None
+        }
+    })
+}
+
+/// Reconcile `index.json` against the `.xc.json` sidecars actually present in
+/// `output_dir`: report audio files a sidecar expects but that are missing,
+/// index entries with no matching sidecar, and sidecars with no index entry.
+/// With `--fix`, the `sounds` array is rebuilt from scratch out of the
+/// on-disk sidecars instead of being patched.
+fn run_verify(args: VerifyArgs, config: &FileConfig) {
+    let output_dir = args
+        .output_dir
+        .or_else(|| config.output_dir.clone())
+        .unwrap_or_else(|| {
+            repo_root()
+                .map(|r| r.join("sounds"))
+                .unwrap_or_else(|| PathBuf::from("."))
+        });
+    let index_path = args
+        .index
+        .or_else(|| config.index.clone())
+        .unwrap_or_else(|| {
             repo_root()
                 .map(|r| r.join("index.json"))
                 .unwrap_or_else(|| output_dir.join("../index.json"))
         });
 
-        let xc_id = rec["id"].as_str().and_then(|s| s.parse::<u64>().ok()).unwrap_or(xc_number);
-        update_index(&index_path, xc_id, en, genus, sp, &audio_filename, &meta_filename);
+    let sidecars = scan_sidecars(&output_dir);
+    eprintln!("Scanned {} sidecar(s) in {}", sidecars.len(), output_dir.display());
+
+    let mut rebuilt_sounds = Vec::new();
+    let mut sidecar_ids = std::collections::HashSet::new();
+    let mut missing_audio = Vec::new();
+
+    for (path, meta) in &sidecars {
+        let meta_filename = path.file_name().unwrap().to_string_lossy().to_string();
+        let base = meta_filename.strip_suffix(".xc.json").unwrap_or(&meta_filename);
+        let xc_id = meta["xc_id"].as_u64().unwrap_or(0);
+        let en = meta["en"].as_str().unwrap_or("").to_string();
+        let species = format!("{} {}", meta["gen"].as_str().unwrap_or(""), meta["sp"].as_str().unwrap_or(""));
+
+        sidecar_ids.insert(xc_id);
+
+        match find_audio_file(&output_dir, base) {
+            Some(audio_path) => {
+                let audio_filename = audio_path.file_name().unwrap().to_string_lossy().to_string();
+                rebuilt_sounds.push(json!({
+                    "filename": audio_filename,
+                    "metadata": meta_filename,
+                    "xc_id": xc_id,
+                    "en": en,
+                    "species": species,
+                    "source": "xeno-canto"
+                }));
+            }
+            None => {
+                missing_audio.push(format!("XC{xc_id}: {meta_filename} has no matching audio file on disk ({base}.*)"));
+            }
+        }
     }
 
-    // Print summary
-    println!("XC{}: {} ({} {})", id, en, genus, sp);
-    println!("Recordist: {}", recordist);
-    println!("License: {}", lic);
-    println!("Attribution: {}", attribution);
+    let existing_index: Value = if index_path.exists() {
+        let content = fs::read_to_string(&index_path).expect("Failed to read index.json");
+        serde_json::from_str(&content).expect("Failed to parse index.json")
+    } else {
+        json!({ "version": 1, "sounds": [] })
+    };
+    let existing_sounds = existing_index["sounds"].as_array().cloned().unwrap_or_default();
+    let indexed_ids: std::collections::HashSet<u64> = existing_sounds
+        .iter()
+        .map(|s| s["xc_id"].as_u64().unwrap_or(0))
+        .collect();
+
+    let missing_sidecar: Vec<u64> = indexed_ids.difference(&sidecar_ids).copied().collect();
+    let unindexed: Vec<u64> = sidecar_ids.difference(&indexed_ids).copied().collect();
+
+    for m in &missing_audio {
+        println!("MISSING AUDIO   {m}");
+    }
+    for id in &missing_sidecar {
+        println!("MISSING SIDECAR index.json references XC{id}, but no .xc.json was found on disk");
+    }
+    for id in &unindexed {
+        println!("NOT INDEXED     XC{id} has a sidecar on disk but no entry in index.json");
+    }
+
+    if missing_audio.is_empty() && missing_sidecar.is_empty() && unindexed.is_empty() {
+        println!("{} is consistent with {}", index_path.display(), output_dir.display());
+        return;
+    }
+
+    if !args.fix {
+        println!("Run with --fix to rebuild index.json's 'sounds' array from the sidecars on disk.");
+        return;
+    }
+
+    let new_index = json!({ "version": 1, "sounds": rebuilt_sounds });
+    let json_str = serde_json::to_string_pretty(&new_index).expect("Failed to serialize index.json");
+    fs::write(&index_path, format!("{}\n", json_str)).expect("Failed to write index.json");
+    println!("Rebuilt {} from {} sidecar(s)", index_path.display(), sidecars.len());
+}
+
+fn main() {
+    // Load .env from current dir or any parent (walks up to repo root)
+    let _ = dotenvy::dotenv();
+
+    let cli = Cli::parse();
+    let config = load_config();
+
+    match cli.command {
+        Command::Fetch(args) => run_fetch(args, &config),
+        Command::Search(args) => run_search(args, &config),
+        Command::Verify(args) => run_verify(args, &config),
+    }
 }